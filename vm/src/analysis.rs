@@ -0,0 +1,292 @@
+// pest. The Elegant Parser
+// Copyright (c) 2018 Dragoș Tiselice
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+
+use pest_meta::ast::{Expr, Rule};
+
+use error::VmError;
+
+/// Checks whether `rules` contains a rule that can re-enter itself without
+/// consuming any input, and if so, returns the cycle of rule names that
+/// proves it.
+///
+/// A rule is left-recursive if it appears in its own "leftmost-call" graph:
+/// the graph with an edge `a -> b` whenever `b` can be the very first rule
+/// `a` attempts to match, without having consumed anything beforehand.
+pub fn check(rules: &[Rule]) -> Result<(), VmError> {
+    let nullable = nullable_set(rules);
+    let graph = leftmost_graph(rules, &nullable);
+
+    if let Some(path) = find_cycle(rules, &graph) {
+        return Err(VmError::LeftRecursion { path });
+    }
+
+    Ok(())
+}
+
+/// Computes, by fixed-point iteration, the set of rules that can match the
+/// empty string.
+fn nullable_set(rules: &[Rule]) -> HashMap<String, bool> {
+    let mut nullable: HashMap<String, bool> = rules.iter().map(|r| (r.name.clone(), false)).collect();
+
+    // Built-in pseudo-rules aren't in `rules`, but they still take part in
+    // `Expr::Ident` lookups: `soi`/`eoi` are zero-width, and `peek`/`pop` can
+    // match an empty stacked string, so all four are nullable. Left as
+    // `false` (the default above), they'd make `a = { soi ~ a }` look
+    // non-left-recursive, when `soi` never consumes input.
+    for builtin in &["soi", "eoi", "peek", "pop"] {
+        nullable.entry((*builtin).to_owned()).or_insert(true);
+    }
+
+    nullable.entry("any".to_owned()).or_insert(false);
+
+    loop {
+        let mut changed = false;
+
+        for rule in rules {
+            if !nullable[&rule.name] && expr_nullable(&rule.expr, &nullable) {
+                nullable.insert(rule.name.clone(), true);
+                changed = true;
+            }
+        }
+
+        if !changed {
+            return nullable;
+        }
+    }
+}
+
+fn expr_nullable(expr: &Expr, nullable: &HashMap<String, bool>) -> bool {
+    match *expr {
+        Expr::Str(_) | Expr::Insens(_) | Expr::Range(..) | Expr::Skip(_) => false,
+        Expr::Opt(_)
+        | Expr::Rep(_)
+        | Expr::RepMax(..)
+        | Expr::PosPred(_)
+        | Expr::NegPred(_) => true,
+        Expr::Seq(ref lhs, ref rhs) => {
+            expr_nullable(lhs, nullable) && expr_nullable(rhs, nullable)
+        }
+        Expr::Choice(ref lhs, ref rhs) => {
+            expr_nullable(lhs, nullable) || expr_nullable(rhs, nullable)
+        }
+        Expr::RepOnce(ref expr) => expr_nullable(expr, nullable),
+        Expr::RepExact(ref expr, num) => num == 0 || expr_nullable(expr, nullable),
+        Expr::RepMin(ref expr, min) => min == 0 || expr_nullable(expr, nullable),
+        Expr::RepMinMax(ref expr, min, _) => min == 0 || expr_nullable(expr, nullable),
+        Expr::Push(ref expr) => expr_nullable(expr, nullable),
+        Expr::Ident(ref name) => *nullable.get(name).unwrap_or(&false)
+    }
+}
+
+/// Builds the leftmost-call graph: `graph[a]` lists every rule `a` may call
+/// before consuming any input.
+fn leftmost_graph(rules: &[Rule], nullable: &HashMap<String, bool>) -> HashMap<String, Vec<String>> {
+    let mut graph = HashMap::new();
+
+    for rule in rules {
+        let mut idents = vec![];
+        leftmost_idents(&rule.expr, nullable, &mut idents);
+        graph.insert(rule.name.clone(), idents);
+    }
+
+    // Built-in pseudo-rules are leaves: they never call back into the grammar.
+    for builtin in &["any", "eoi", "soi", "peek", "pop"] {
+        graph.entry((*builtin).to_owned()).or_insert_with(Vec::new);
+    }
+
+    graph
+}
+
+fn leftmost_idents(expr: &Expr, nullable: &HashMap<String, bool>, idents: &mut Vec<String>) {
+    match *expr {
+        Expr::Str(_) | Expr::Insens(_) | Expr::Range(..) | Expr::Skip(_) => (),
+        Expr::Ident(ref name) => idents.push(name.clone()),
+        Expr::Seq(ref lhs, ref rhs) => {
+            leftmost_idents(lhs, nullable, idents);
+
+            if expr_nullable(lhs, nullable) {
+                leftmost_idents(rhs, nullable, idents);
+            }
+        }
+        Expr::Choice(ref lhs, ref rhs) => {
+            leftmost_idents(lhs, nullable, idents);
+            leftmost_idents(rhs, nullable, idents);
+        }
+        Expr::Opt(ref expr)
+        | Expr::Rep(ref expr)
+        | Expr::RepOnce(ref expr)
+        | Expr::RepExact(ref expr, _)
+        | Expr::RepMin(ref expr, _)
+        | Expr::RepMax(ref expr, _)
+        | Expr::RepMinMax(ref expr, _, _)
+        | Expr::PosPred(ref expr)
+        | Expr::NegPred(ref expr)
+        | Expr::Push(ref expr) => leftmost_idents(expr, nullable, idents)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Color {
+    White,
+    Gray,
+    Black
+}
+
+/// Runs a DFS over the leftmost-call graph looking for a cycle, returning
+/// the rule names that form it in call order (starting and ending on the
+/// same rule) if one exists.
+fn find_cycle(rules: &[Rule], graph: &HashMap<String, Vec<String>>) -> Option<Vec<String>> {
+    let mut colors: HashMap<&str, Color> =
+        graph.keys().map(|name| (name.as_str(), Color::White)).collect();
+    let mut stack = vec![];
+
+    for rule in rules {
+        if colors[rule.name.as_str()] == Color::White {
+            if let Some(path) = dfs(&rule.name, graph, &mut colors, &mut stack) {
+                return Some(path);
+            }
+        }
+    }
+
+    None
+}
+
+fn dfs<'a>(
+    node: &'a str,
+    graph: &'a HashMap<String, Vec<String>>,
+    colors: &mut HashMap<&'a str, Color>,
+    stack: &mut Vec<&'a str>
+) -> Option<Vec<String>> {
+    match colors.get(node) {
+        Some(Color::Black) => return None,
+        Some(Color::Gray) => {
+            let start = stack.iter().position(|name| *name == node).unwrap();
+            let mut path: Vec<String> = stack[start..].iter().map(|name| (*name).to_owned()).collect();
+            path.push(node.to_owned());
+
+            return Some(path);
+        }
+        _ => ()
+    }
+
+    colors.insert(node, Color::Gray);
+    stack.push(node);
+
+    if let Some(edges) = graph.get(node) {
+        for next in edges {
+            if let Some(path) = dfs(next, graph, colors, stack) {
+                return Some(path);
+            }
+        }
+    }
+
+    stack.pop();
+    colors.insert(node, Color::Black);
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pest_meta::ast::RuleType;
+
+    fn ident(name: &str) -> Expr {
+        Expr::Ident(name.to_owned())
+    }
+
+    fn str_lit(string: &str) -> Expr {
+        Expr::Str(string.to_owned())
+    }
+
+    fn rule(name: &str, expr: Expr) -> Rule {
+        Rule {
+            name: name.to_owned(),
+            ty: RuleType::Normal,
+            expr
+        }
+    }
+
+    #[test]
+    fn detects_direct_left_recursion() {
+        // expr = { expr ~ "+" ~ term }
+        let rules = vec![
+            rule(
+                "expr",
+                Expr::Seq(
+                    Box::new(ident("expr")),
+                    Box::new(Expr::Seq(Box::new(str_lit("+")), Box::new(ident("term"))))
+                )
+            ),
+            rule("term", str_lit("a"))
+        ];
+
+        match check(&rules) {
+            Err(VmError::LeftRecursion { path }) => assert_eq!(path, vec!["expr".to_owned(), "expr".to_owned()]),
+            other => panic!("expected left-recursion error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn detects_indirect_left_recursion() {
+        // a = { b }
+        // b = { a }
+        let rules = vec![rule("a", ident("b")), rule("b", ident("a"))];
+
+        assert!(check(&rules).is_err());
+    }
+
+    #[test]
+    fn allows_recursion_after_consuming_input() {
+        // expr = { term ~ "+" ~ expr }
+        let rules = vec![
+            rule(
+                "expr",
+                Expr::Seq(
+                    Box::new(ident("term")),
+                    Box::new(Expr::Seq(Box::new(str_lit("+")), Box::new(ident("expr"))))
+                )
+            ),
+            rule("term", str_lit("a"))
+        ];
+
+        assert!(check(&rules).is_ok());
+    }
+
+    #[test]
+    fn nullable_prefix_exposes_left_recursion() {
+        // expr = { opt ~ expr }
+        // opt = { "x"? }
+        let rules = vec![
+            rule("expr", Expr::Seq(Box::new(ident("opt")), Box::new(ident("expr")))),
+            rule("opt", Expr::Opt(Box::new(str_lit("x"))))
+        ];
+
+        assert!(check(&rules).is_err());
+    }
+
+    #[test]
+    fn detects_left_recursion_behind_soi() {
+        // a = { soi ~ a }
+        let rules = vec![rule("a", Expr::Seq(Box::new(ident("soi")), Box::new(ident("a"))))];
+
+        assert!(check(&rules).is_err());
+    }
+
+    #[test]
+    fn detects_left_recursion_behind_eoi() {
+        // a = { eoi ~ a }
+        let rules = vec![rule("a", Expr::Seq(Box::new(ident("eoi")), Box::new(ident("a"))))];
+
+        assert!(check(&rules).is_err());
+    }
+}
@@ -0,0 +1,256 @@
+// pest. The Elegant Parser
+// Copyright (c) 2018 Dragoș Tiselice
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+
+use pest_meta::ast::{Expr, Rule, RuleType};
+
+use error::VmError;
+use unescape;
+
+/// A rule's dispatch tag, precomputed once so `Vm::dispatch` can switch on
+/// a single enum instead of re-checking the rule's name against
+/// `"whitespace"`/`"comment"` and matching on `RuleType` on every call.
+#[derive(Clone, Copy)]
+pub enum RuleKind {
+    Normal,
+    Silent,
+    Atomic,
+    CompoundAtomic,
+    NonAtomic,
+    WhitespaceOrCommentNormal,
+    WhitespaceOrCommentSilent,
+    WhitespaceOrCommentAtomic,
+    WhitespaceOrCommentCompoundAtomic,
+    WhitespaceOrCommentNonAtomic
+}
+
+impl RuleKind {
+    fn new(rule: &Rule) -> RuleKind {
+        let is_whitespace_or_comment = rule.name == "whitespace" || rule.name == "comment";
+
+        match (is_whitespace_or_comment, &rule.ty) {
+            (false, &RuleType::Normal) => RuleKind::Normal,
+            (false, &RuleType::Silent) => RuleKind::Silent,
+            (false, &RuleType::Atomic) => RuleKind::Atomic,
+            (false, &RuleType::CompoundAtomic) => RuleKind::CompoundAtomic,
+            (false, &RuleType::NonAtomic) => RuleKind::NonAtomic,
+            (true, &RuleType::Normal) => RuleKind::WhitespaceOrCommentNormal,
+            (true, &RuleType::Silent) => RuleKind::WhitespaceOrCommentSilent,
+            (true, &RuleType::Atomic) => RuleKind::WhitespaceOrCommentAtomic,
+            (true, &RuleType::CompoundAtomic) => RuleKind::WhitespaceOrCommentCompoundAtomic,
+            (true, &RuleType::NonAtomic) => RuleKind::WhitespaceOrCommentNonAtomic
+        }
+    }
+}
+
+/// A resolved reference to either a built-in pseudo-rule or a user-defined
+/// rule, computed once at compile time so matching no longer hashes the
+/// rule name.
+#[derive(Clone, Copy)]
+pub enum RuleRef {
+    Any,
+    Eoi,
+    Soi,
+    Peek,
+    Pop,
+    Rule(usize)
+}
+
+pub struct CompiledRule {
+    pub name: String,
+    pub kind: RuleKind,
+    pub expr: CompiledExpr
+}
+
+pub enum CompiledExpr {
+    Str(String),
+    Insens(String),
+    Range(char, char),
+    Ident(RuleRef),
+    PosPred(Box<CompiledExpr>),
+    NegPred(Box<CompiledExpr>),
+    Seq(Box<CompiledExpr>, Box<CompiledExpr>),
+    Choice(Box<CompiledExpr>, Box<CompiledExpr>),
+    Opt(Box<CompiledExpr>),
+    Rep(Box<CompiledExpr>),
+    RepOnce(Box<CompiledExpr>),
+    RepExact(Box<CompiledExpr>, u32),
+    RepMin(Box<CompiledExpr>, u32),
+    RepMax(Box<CompiledExpr>, u32),
+    RepMinMax(Box<CompiledExpr>, u32, u32),
+    Push(Box<CompiledExpr>),
+    Skip(Vec<String>)
+}
+
+/// Lowers a grammar's `Vec<Rule>` into a `Vec<CompiledRule>`: literals are
+/// unescaped once, `Ident`s are resolved to a direct `RuleRef` instead of a
+/// rule name, and each rule's atomicity/whitespace handling is precomputed
+/// into a `RuleKind`.
+pub fn compile(rules: Vec<Rule>) -> Result<(Vec<CompiledRule>, HashMap<String, usize>), VmError> {
+    let indices: HashMap<String, usize> = rules
+        .iter()
+        .enumerate()
+        .map(|(i, rule)| (rule.name.clone(), i))
+        .collect();
+
+    let compiled = rules
+        .iter()
+        .map(|rule| {
+            Ok(CompiledRule {
+                name: rule.name.clone(),
+                kind: RuleKind::new(rule),
+                expr: compile_expr(&rule.name, &rule.expr, &indices)?
+            })
+        })
+        .collect::<Result<Vec<_>, VmError>>()?;
+
+    Ok((compiled, indices))
+}
+
+fn resolve(rule: &str, name: &str, indices: &HashMap<String, usize>) -> Result<RuleRef, VmError> {
+    match name {
+        "any" => Ok(RuleRef::Any),
+        "eoi" => Ok(RuleRef::Eoi),
+        "soi" => Ok(RuleRef::Soi),
+        "peek" => Ok(RuleRef::Peek),
+        "pop" => Ok(RuleRef::Pop),
+        _ => indices.get(name).cloned().map(RuleRef::Rule).ok_or_else(|| {
+            VmError::UndefinedRule {
+                rule: rule.to_owned(),
+                undefined: name.to_owned()
+            }
+        })
+    }
+}
+
+fn literal(rule: &str, string: &str) -> Result<String, VmError> {
+    unescape(string).ok_or_else(|| VmError::InvalidLiteral {
+        rule: rule.to_owned(),
+        literal: string.to_owned()
+    })
+}
+
+fn char_literal(rule: &str, string: &str) -> Result<char, VmError> {
+    literal(rule, string)?.chars().next().ok_or_else(|| VmError::InvalidLiteral {
+        rule: rule.to_owned(),
+        literal: string.to_owned()
+    })
+}
+
+fn compile_expr(
+    rule: &str,
+    expr: &Expr,
+    indices: &HashMap<String, usize>
+) -> Result<CompiledExpr, VmError> {
+    Ok(match *expr {
+        Expr::Str(ref string) => CompiledExpr::Str(literal(rule, string)?),
+        Expr::Insens(ref string) => CompiledExpr::Insens(literal(rule, string)?),
+        Expr::Range(ref start, ref end) => {
+            CompiledExpr::Range(char_literal(rule, start)?, char_literal(rule, end)?)
+        }
+        Expr::Ident(ref name) => CompiledExpr::Ident(resolve(rule, name, indices)?),
+        Expr::PosPred(ref expr) => {
+            CompiledExpr::PosPred(Box::new(compile_expr(rule, expr, indices)?))
+        }
+        Expr::NegPred(ref expr) => {
+            CompiledExpr::NegPred(Box::new(compile_expr(rule, expr, indices)?))
+        }
+        Expr::Seq(ref lhs, ref rhs) => CompiledExpr::Seq(
+            Box::new(compile_expr(rule, lhs, indices)?),
+            Box::new(compile_expr(rule, rhs, indices)?)
+        ),
+        Expr::Choice(ref lhs, ref rhs) => CompiledExpr::Choice(
+            Box::new(compile_expr(rule, lhs, indices)?),
+            Box::new(compile_expr(rule, rhs, indices)?)
+        ),
+        Expr::Opt(ref expr) => CompiledExpr::Opt(Box::new(compile_expr(rule, expr, indices)?)),
+        Expr::Rep(ref expr) => CompiledExpr::Rep(Box::new(compile_expr(rule, expr, indices)?)),
+        Expr::RepOnce(ref expr) => {
+            CompiledExpr::RepOnce(Box::new(compile_expr(rule, expr, indices)?))
+        }
+        Expr::RepExact(ref expr, num) => {
+            CompiledExpr::RepExact(Box::new(compile_expr(rule, expr, indices)?), num)
+        }
+        Expr::RepMin(ref expr, min) => {
+            CompiledExpr::RepMin(Box::new(compile_expr(rule, expr, indices)?), min)
+        }
+        Expr::RepMax(ref expr, max) => {
+            CompiledExpr::RepMax(Box::new(compile_expr(rule, expr, indices)?), max)
+        }
+        Expr::RepMinMax(ref expr, min, max) => {
+            CompiledExpr::RepMinMax(Box::new(compile_expr(rule, expr, indices)?), min, max)
+        }
+        Expr::Push(ref expr) => CompiledExpr::Push(Box::new(compile_expr(rule, expr, indices)?)),
+        Expr::Skip(ref strings) => CompiledExpr::Skip(strings.clone())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(name: &str, ty: RuleType, expr: Expr) -> Rule {
+        Rule {
+            name: name.to_owned(),
+            ty,
+            expr
+        }
+    }
+
+    #[test]
+    fn unescapes_literals_once() {
+        let rules = vec![rule("a", RuleType::Normal, Expr::Str(r"\n".to_owned()))];
+        let (compiled, _) = compile(rules).unwrap();
+
+        match compiled[0].expr {
+            CompiledExpr::Str(ref string) => assert_eq!(string, "\n"),
+            _ => panic!("expected a Str")
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_literals() {
+        let rules = vec![rule("a", RuleType::Normal, Expr::Str(r"\w".to_owned()))];
+
+        assert!(compile(rules).is_err());
+    }
+
+    #[test]
+    fn resolves_idents_to_indices() {
+        let rules = vec![
+            rule("a", RuleType::Normal, Expr::Ident("b".to_owned())),
+            rule("b", RuleType::Normal, Expr::Str("x".to_owned()))
+        ];
+        let (compiled, indices) = compile(rules).unwrap();
+
+        match compiled[0].expr {
+            CompiledExpr::Ident(RuleRef::Rule(index)) => assert_eq!(index, indices["b"]),
+            _ => panic!("expected a resolved Ident")
+        }
+    }
+
+    #[test]
+    fn resolves_builtins() {
+        let rules = vec![rule("a", RuleType::Normal, Expr::Ident("eoi".to_owned()))];
+        let (compiled, _) = compile(rules).unwrap();
+
+        match compiled[0].expr {
+            CompiledExpr::Ident(RuleRef::Eoi) => (),
+            _ => panic!("expected a resolved built-in")
+        }
+    }
+
+    #[test]
+    fn rejects_undefined_rules() {
+        let rules = vec![rule("a", RuleType::Normal, Expr::Ident("nope".to_owned()))];
+
+        assert!(compile(rules).is_err());
+    }
+}
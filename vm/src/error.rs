@@ -0,0 +1,61 @@
+// pest. The Elegant Parser
+// Copyright (c) 2018 Dragoș Tiselice
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use std::error;
+use std::fmt;
+
+/// An error produced while turning a `Vec<Rule>` into a `Vm`, as opposed to
+/// an error produced while parsing an input with one.
+#[derive(Debug, PartialEq)]
+pub enum VmError {
+    /// A rule can re-enter itself without consuming any input. `path` lists
+    /// the rule names forming the cycle, in call order, starting and ending
+    /// on the same rule.
+    LeftRecursion { path: Vec<String> },
+    /// A `Str`, `Insens`, or `Range` literal could not be unescaped.
+    InvalidLiteral { rule: String, literal: String },
+    /// An `Ident` refers to a rule that was never defined.
+    UndefinedRule { rule: String, undefined: String }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VmError::LeftRecursion { ref path } => {
+                write!(f, "rule ")?;
+
+                for (i, rule) in path.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " -> ")?;
+                    }
+
+                    write!(f, "{}", rule)?;
+                }
+
+                write!(f, " is left-recursive")
+            }
+            VmError::InvalidLiteral { ref rule, ref literal } => {
+                write!(f, "rule {} contains an invalid literal: {}", rule, literal)
+            }
+            VmError::UndefinedRule { ref rule, ref undefined } => {
+                write!(f, "rule {} refers to undefined rule {}", rule, undefined)
+            }
+        }
+    }
+}
+
+impl error::Error for VmError {
+    fn description(&self) -> &str {
+        match *self {
+            VmError::LeftRecursion { .. } => "left-recursive rule cycle",
+            VmError::InvalidLiteral { .. } => "invalid literal",
+            VmError::UndefinedRule { .. } => "undefined rule"
+        }
+    }
+}
@@ -10,24 +10,55 @@
 extern crate pest;
 extern crate pest_meta;
 
+use std::cell::{Cell, RefCell};
 use std::char;
 use std::collections::HashMap;
 
 use pest::{Atomicity, Error, ParserState, Position};
 use pest::iterators::Pairs;
 
-use pest_meta::ast::{Expr, Rule, RuleType};
+use pest_meta::ast::Rule;
 
+mod analysis;
+mod compile;
+mod error;
 mod macros;
+mod trace;
 
+use compile::{CompiledExpr, CompiledRule, RuleRef};
+
+pub use error::VmError;
+pub use trace::{TraceEvent, TraceOutcome};
+
+/// Note: the trace bookkeeping below makes `Vm` `!Sync`, so a single `Vm`
+/// can no longer be shared across threads without synchronization.
 pub struct Vm {
-    rules: HashMap<String, Rule>
+    rules: Vec<CompiledRule>,
+    names: HashMap<String, usize>,
+    whitespace: Option<usize>,
+    comment: Option<usize>,
+    tracing: Cell<bool>,
+    trace: RefCell<Option<Vec<TraceEvent>>>,
+    depth: Cell<usize>
 }
 
 impl Vm {
-    pub fn new(rules: Vec<Rule>) -> Vm {
-        let rules = rules.into_iter().map(|r| (r.name.clone(), r)).collect();
-        Vm { rules }
+    pub fn new(rules: Vec<Rule>) -> Result<Vm, VmError> {
+        analysis::check(&rules)?;
+
+        let (rules, names) = compile::compile(rules)?;
+        let whitespace = names.get("whitespace").cloned();
+        let comment = names.get("comment").cloned();
+
+        Ok(Vm {
+            rules,
+            names,
+            whitespace,
+            comment,
+            tracing: Cell::new(false),
+            trace: RefCell::new(None),
+            depth: Cell::new(0)
+        })
     }
 
     pub fn parse<'a, 'i>(
@@ -35,165 +66,251 @@ impl Vm {
         rule: &'a str,
         input: &'i str
     ) -> Result<Pairs<'i, &str>, Error<'i, &str>> {
+        let rule = self.resolve(rule);
+
         pest::state(input, |mut state, pos| {
             self.parse_rule(rule, pos, &mut state)
         })
     }
 
-    fn parse_rule<'a, 'i>(
+    /// Like `parse`, but also returns the `TraceEvent`s of every rule attempt
+    /// (an `Enter` event, then a `Matched` or `Failed` one), in call order,
+    /// with `depth` tracking nesting. Useful for tooling that
+    /// wants to show exactly which rules were tried at which positions.
+    pub fn parse_traced<'a, 'i>(
         &'a self,
         rule: &'a str,
+        input: &'i str
+    ) -> (Result<Pairs<'i, &str>, Error<'i, &str>>, Vec<TraceEvent>) {
+        self.tracing.set(true);
+        *self.trace.borrow_mut() = Some(vec![]);
+        self.depth.set(0);
+
+        let result = self.parse(rule, input);
+
+        self.tracing.set(false);
+        let events = self.trace.borrow_mut().take().unwrap_or_default();
+
+        (result, events)
+    }
+
+    fn trace_enter(&self, rule: &str, pos: usize, depth: usize) {
+        if let Some(ref mut events) = *self.trace.borrow_mut() {
+            events.push(TraceEvent {
+                rule: rule.to_owned(),
+                pos,
+                depth,
+                outcome: TraceOutcome::Enter
+            });
+        }
+    }
+
+    fn trace_exit(&self, rule: &str, pos: usize, depth: usize, matched: bool) {
+        if let Some(ref mut events) = *self.trace.borrow_mut() {
+            let outcome = if matched {
+                TraceOutcome::Matched
+            } else {
+                TraceOutcome::Failed
+            };
+
+            events.push(TraceEvent {
+                rule: rule.to_owned(),
+                pos,
+                depth,
+                outcome
+            });
+        }
+    }
+
+    fn rule_name(&self, rule: RuleRef) -> &str {
+        match rule {
+            RuleRef::Any => "any",
+            RuleRef::Eoi => "eoi",
+            RuleRef::Soi => "soi",
+            RuleRef::Peek => "peek",
+            RuleRef::Pop => "pop",
+            RuleRef::Rule(index) => &self.rules[index].name
+        }
+    }
+
+    fn resolve(&self, rule: &str) -> RuleRef {
+        match rule {
+            "any" => RuleRef::Any,
+            "eoi" => RuleRef::Eoi,
+            "soi" => RuleRef::Soi,
+            "peek" => RuleRef::Peek,
+            "pop" => RuleRef::Pop,
+            _ => self.names
+                .get(rule)
+                .cloned()
+                .map(RuleRef::Rule)
+                .unwrap_or_else(|| panic!("undefined rule {}", rule))
+        }
+    }
+
+    fn parse_rule<'a, 'i>(
+        &'a self,
+        rule: RuleRef,
+        pos: Position<'i>,
+        state: &mut ParserState<'i, &'a str>
+    ) -> Result<Position<'i>, Position<'i>> {
+        if !self.tracing.get() {
+            return self.eval_rule(rule, pos, state);
+        }
+
+        let name = self.rule_name(rule);
+        let entry = pos.pos();
+        let depth = self.depth.get();
+
+        self.trace_enter(name, entry, depth);
+        self.depth.set(depth + 1);
+
+        let result = self.eval_rule(rule, pos, state);
+
+        self.depth.set(depth);
+        self.trace_exit(name, entry, depth, result.is_ok());
+
+        result
+    }
+
+    fn eval_rule<'a, 'i>(
+        &'a self,
+        rule: RuleRef,
         pos: Position<'i>,
         state: &mut ParserState<'i, &'a str>
     ) -> Result<Position<'i>, Position<'i>> {
         match rule {
-            "any" => return pos.skip(1),
-            "eoi" => return state.rule("eoi", pos, |_, pos| pos.at_end()),
-            "soi" => return pos.at_start(),
-            "peek" => {
-                return {
+            RuleRef::Any => pos.skip(1),
+            RuleRef::Eoi => state.rule("eoi", pos, |_, pos| pos.at_end()),
+            RuleRef::Soi => pos.at_start(),
+            RuleRef::Peek => {
+                let string = state
+                    .stack
+                    .last()
+                    .expect("peek was called on empty stack")
+                    .as_str();
+
+                pos.match_string(string)
+            }
+            RuleRef::Pop => {
+                let result = {
                     let string = state
                         .stack
                         .last()
-                        .expect("peek was called on empty stack")
+                        .expect("pop was called on empty stack")
                         .as_str();
+
                     pos.match_string(string)
+                };
+
+                if result.is_ok() {
+                    state.stack.pop().unwrap();
                 }
+
+                result
             }
-            "pop" => {
-                return {
-                    let pos = {
-                        let string = state
-                            .stack
-                            .last()
-                            .expect("pop was called on empty stack")
-                            .as_str();
-
-                        pos.match_string(string)
-                    };
-
-                    if pos.is_ok() {
-                        state.stack.pop().unwrap();
-                    }
+            RuleRef::Rule(index) => {
+                let rule = &self.rules[index];
 
-                    pos
-                }
+                self.dispatch(rule, pos, state)
             }
-            _ => ()
-        };
+        }
+    }
 
-        if let Some(rule) = self.rules.get(rule) {
-            if &rule.name == "whitespace" || &rule.name == "comment" {
-                match rule.ty {
-                    RuleType::Normal => state.rule(&rule.name, pos, |state, pos| {
-                        state.atomic(Atomicity::Atomic, move |state| {
-                            self.parse_expr(&rule.expr, pos, state)
-                        })
-                    }),
-                    RuleType::Silent => state.atomic(Atomicity::Atomic, move |state| {
-                        self.parse_expr(&rule.expr, pos, state)
-                    }),
-                    RuleType::Atomic => state.rule(&rule.name, pos, |state, pos| {
-                        state.atomic(Atomicity::Atomic, move |state| {
-                            self.parse_expr(&rule.expr, pos, state)
-                        })
-                    }),
-                    RuleType::CompoundAtomic => {
-                        state.atomic(Atomicity::CompoundAtomic, move |state| {
-                            state.rule(&rule.name, pos, |state, pos| {
-                                self.parse_expr(&rule.expr, pos, state)
-                            })
-                        })
-                    }
-                    RuleType::NonAtomic => state.atomic(Atomicity::Atomic, move |state| {
-                        state.rule(&rule.name, pos, |state, pos| {
-                            self.parse_expr(&rule.expr, pos, state)
-                        })
-                    })
-                }
-            } else {
-                match rule.ty {
-                    RuleType::Normal => state.rule(&rule.name, pos, |state, pos| {
+    fn dispatch<'a, 'i>(
+        &'a self,
+        rule: &'a CompiledRule,
+        pos: Position<'i>,
+        state: &mut ParserState<'i, &'a str>
+    ) -> Result<Position<'i>, Position<'i>> {
+        use compile::RuleKind::*;
+
+        match rule.kind {
+            Normal => state.rule(&rule.name, pos, |state, pos| {
+                self.parse_expr(&rule.expr, pos, state)
+            }),
+            Silent => self.parse_expr(&rule.expr, pos, state),
+            Atomic => state.rule(&rule.name, pos, |state, pos| {
+                state.atomic(Atomicity::Atomic, move |state| {
+                    self.parse_expr(&rule.expr, pos, state)
+                })
+            }),
+            CompoundAtomic => state.atomic(Atomicity::CompoundAtomic, move |state| {
+                state.rule(&rule.name, pos, |state, pos| {
+                    self.parse_expr(&rule.expr, pos, state)
+                })
+            }),
+            NonAtomic => state.atomic(Atomicity::NonAtomic, move |state| {
+                state.rule(&rule.name, pos, |state, pos| {
+                    self.parse_expr(&rule.expr, pos, state)
+                })
+            }),
+            WhitespaceOrCommentNormal => state.rule(&rule.name, pos, |state, pos| {
+                state.atomic(Atomicity::Atomic, move |state| {
+                    self.parse_expr(&rule.expr, pos, state)
+                })
+            }),
+            WhitespaceOrCommentSilent => state.atomic(Atomicity::Atomic, move |state| {
+                self.parse_expr(&rule.expr, pos, state)
+            }),
+            WhitespaceOrCommentAtomic => state.rule(&rule.name, pos, |state, pos| {
+                state.atomic(Atomicity::Atomic, move |state| {
+                    self.parse_expr(&rule.expr, pos, state)
+                })
+            }),
+            WhitespaceOrCommentCompoundAtomic => {
+                state.atomic(Atomicity::CompoundAtomic, move |state| {
+                    state.rule(&rule.name, pos, |state, pos| {
                         self.parse_expr(&rule.expr, pos, state)
-                    }),
-                    RuleType::Silent => self.parse_expr(&rule.expr, pos, state),
-                    RuleType::Atomic => state.rule(&rule.name, pos, |state, pos| {
-                        state.atomic(Atomicity::Atomic, move |state| {
-                            self.parse_expr(&rule.expr, pos, state)
-                        })
-                    }),
-                    RuleType::CompoundAtomic => {
-                        state.atomic(Atomicity::CompoundAtomic, move |state| {
-                            state.rule(&rule.name, pos, |state, pos| {
-                                self.parse_expr(&rule.expr, pos, state)
-                            })
-                        })
-                    }
-                    RuleType::NonAtomic => state.atomic(Atomicity::NonAtomic, move |state| {
-                        state.rule(&rule.name, pos, |state, pos| {
-                            self.parse_expr(&rule.expr, pos, state)
-                        })
                     })
-                }
+                })
             }
-        } else {
-            panic!("undefined rule {}", rule);
+            WhitespaceOrCommentNonAtomic => state.atomic(Atomicity::Atomic, move |state| {
+                state.rule(&rule.name, pos, |state, pos| {
+                    self.parse_expr(&rule.expr, pos, state)
+                })
+            })
         }
     }
 
     fn parse_expr<'a, 'i>(
         &'a self,
-        expr: &'a Expr,
+        expr: &'a CompiledExpr,
         pos: Position<'i>,
         state: &mut ParserState<'i, &'a str>
     ) -> Result<Position<'i>, Position<'i>> {
         match *expr {
-            Expr::Str(ref string) => {
-                pos.match_string(&unescape(string).expect("incorrect string literal"))
-            }
-            Expr::Insens(ref string) => {
-                pos.match_insensitive(&unescape(string).expect("incorrect string literal"))
-            }
-            Expr::Range(ref start, ref end) => {
-                let start = unescape(start)
-                    .expect("incorrect char literal")
-                    .chars()
-                    .next()
-                    .expect("empty char literal");
-                let end = unescape(end)
-                    .expect("incorrect char literal")
-                    .chars()
-                    .next()
-                    .expect("empty char literal");
-
-                pos.match_range(start..end)
-            }
-            Expr::Ident(ref name) => self.parse_rule(name, pos, state),
-            Expr::PosPred(ref expr) => state.lookahead(true, move |state| {
+            CompiledExpr::Str(ref string) => pos.match_string(string),
+            CompiledExpr::Insens(ref string) => pos.match_insensitive(string),
+            CompiledExpr::Range(start, end) => pos.match_range(start..end),
+            CompiledExpr::Ident(rule) => self.parse_rule(rule, pos, state),
+            CompiledExpr::PosPred(ref expr) => state.lookahead(true, move |state| {
                 pos.lookahead(true, |pos| self.parse_expr(&*expr, pos, state))
             }),
-            Expr::NegPred(ref expr) => state.lookahead(false, move |state| {
+            CompiledExpr::NegPred(ref expr) => state.lookahead(false, move |state| {
                 pos.lookahead(false, |pos| self.parse_expr(&*expr, pos, state))
             }),
-            Expr::Seq(ref lhs, ref rhs) => state.sequence(move |state| {
+            CompiledExpr::Seq(ref lhs, ref rhs) => state.sequence(move |state| {
                 pos.sequence(|pos| {
                     self.parse_expr(&*lhs, pos, state)
                         .and_then(|pos| self.skip(pos, state))
                         .and_then(|pos| self.parse_expr(&*rhs, pos, state))
                 })
             }),
-            Expr::Choice(ref lhs, ref rhs) => self.parse_expr(&*lhs, pos, state)
+            CompiledExpr::Choice(ref lhs, ref rhs) => self.parse_expr(&*lhs, pos, state)
                 .or_else(|pos| self.parse_expr(&*rhs, pos, state)),
-            Expr::Opt(ref expr) => pos.optional(|pos| self.parse_expr(&*expr, pos, state)),
-            Expr::Rep(ref expr) => self.repeat(expr, None, None, pos, state),
-            Expr::RepOnce(ref expr) => self.repeat(expr, Some(1), None, pos, state),
-            Expr::RepExact(ref expr, num) => self.repeat(expr, Some(num), Some(num), pos, state),
-            Expr::RepMin(ref expr, min) => self.repeat(expr, Some(min), None, pos, state),
-            Expr::RepMax(ref expr, max) => self.repeat(expr, None, Some(max), pos, state),
-            Expr::RepMinMax(ref expr, min, max) => {
+            CompiledExpr::Opt(ref expr) => pos.optional(|pos| self.parse_expr(&*expr, pos, state)),
+            CompiledExpr::Rep(ref expr) => self.repeat(expr, None, None, pos, state),
+            CompiledExpr::RepOnce(ref expr) => self.repeat(expr, Some(1), None, pos, state),
+            CompiledExpr::RepExact(ref expr, num) => {
+                self.repeat(expr, Some(num), Some(num), pos, state)
+            }
+            CompiledExpr::RepMin(ref expr, min) => self.repeat(expr, Some(min), None, pos, state),
+            CompiledExpr::RepMax(ref expr, max) => self.repeat(expr, None, Some(max), pos, state),
+            CompiledExpr::RepMinMax(ref expr, min, max) => {
                 self.repeat(expr, Some(min), Some(max), pos, state)
             }
-            Expr::Push(ref expr) => {
+            CompiledExpr::Push(ref expr) => {
                 let start = pos.clone();
 
                 match self.parse_expr(&*expr, pos, state) {
@@ -204,7 +321,7 @@ impl Vm {
                     Err(pos) => Err(pos)
                 }
             }
-            Expr::Skip(ref strings) => strings[1..].iter().fold(
+            CompiledExpr::Skip(ref strings) => strings[1..].iter().fold(
                 pos.clone().skip_until(&strings[0]),
                 |result, string| match (result, pos.clone().skip_until(string)) {
                     (Ok(lhs), Ok(rhs)) => {
@@ -224,7 +341,7 @@ impl Vm {
 
     fn repeat<'a, 'i>(
         &'a self,
-        expr: &'a Expr,
+        expr: &'a CompiledExpr,
         min: Option<u32>,
         max: Option<u32>,
         pos: Position<'i>,
@@ -278,34 +395,36 @@ impl Vm {
         pos: Position<'i>,
         state: &mut ParserState<'i, &'a str>
     ) -> Result<Position<'i>, Position<'i>> {
-        match (
-            self.rules.contains_key("whitespace"),
-            self.rules.contains_key("comment")
-        ) {
-            (false, false) => Ok(pos),
-            (true, false) => if state.atomicity == Atomicity::NonAtomic {
-                pos.repeat(|pos| self.parse_rule("whitespace", pos, state))
+        match (self.whitespace, self.comment) {
+            (None, None) => Ok(pos),
+            (Some(whitespace), None) => if state.atomicity == Atomicity::NonAtomic {
+                pos.repeat(|pos| self.parse_rule(RuleRef::Rule(whitespace), pos, state))
             } else {
                 Ok(pos)
             },
-            (false, true) => if state.atomicity == Atomicity::NonAtomic {
-                pos.repeat(|pos| self.parse_rule("comment", pos, state))
+            (None, Some(comment)) => if state.atomicity == Atomicity::NonAtomic {
+                pos.repeat(|pos| self.parse_rule(RuleRef::Rule(comment), pos, state))
             } else {
                 Ok(pos)
             },
-            (true, true) => if state.atomicity == Atomicity::NonAtomic {
+            (Some(whitespace), Some(comment)) => if state.atomicity == Atomicity::NonAtomic {
                 state.sequence(move |state| {
                     pos.sequence(|pos| {
-                        pos.repeat(|pos| self.parse_rule("whitespace", pos, state))
+                        pos.repeat(|pos| self.parse_rule(RuleRef::Rule(whitespace), pos, state))
                             .and_then(|pos| {
                                 pos.repeat(|pos| {
                                     state.sequence(move |state| {
                                         pos.sequence(|pos| {
-                                            self.parse_rule("comment", pos, state).and_then(|pos| {
-                                                pos.repeat(|pos| {
-                                                    self.parse_rule("whitespace", pos, state)
+                                            self.parse_rule(RuleRef::Rule(comment), pos, state)
+                                                .and_then(|pos| {
+                                                    pos.repeat(|pos| {
+                                                        self.parse_rule(
+                                                            RuleRef::Rule(whitespace),
+                                                            pos,
+                                                            state
+                                                        )
+                                                    })
                                                 })
-                                            })
                                         })
                                     })
                                 })
@@ -379,6 +498,89 @@ fn unescape(string: &str) -> Option<String> {
 mod tests {
     use super::*;
 
+    use pest_meta::ast::{Expr, RuleType};
+
+    fn rule(name: &str, expr: Expr) -> Rule {
+        Rule {
+            name: name.to_owned(),
+            ty: RuleType::Normal,
+            expr
+        }
+    }
+
+    #[test]
+    fn parse_traced_records_nested_rule_attempts() {
+        // a = { b ~ "y" }
+        // b = { "x" }
+        let rules = vec![
+            rule(
+                "a",
+                Expr::Seq(
+                    Box::new(Expr::Ident("b".to_owned())),
+                    Box::new(Expr::Str("y".to_owned()))
+                )
+            ),
+            rule("b", Expr::Str("x".to_owned()))
+        ];
+        let vm = Vm::new(rules).unwrap();
+
+        let (result, events) = vm.parse_traced("a", "xy");
+
+        assert!(result.is_ok());
+        assert_eq!(
+            events,
+            vec![
+                TraceEvent { rule: "a".to_owned(), pos: 0, depth: 0, outcome: TraceOutcome::Enter },
+                TraceEvent { rule: "b".to_owned(), pos: 0, depth: 1, outcome: TraceOutcome::Enter },
+                TraceEvent { rule: "b".to_owned(), pos: 0, depth: 1, outcome: TraceOutcome::Matched },
+                TraceEvent { rule: "a".to_owned(), pos: 0, depth: 0, outcome: TraceOutcome::Matched }
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_traced_records_backtracking_choice() {
+        // a = { b | c }
+        // b = { "x" }
+        // c = { "y" }
+        let rules = vec![
+            rule(
+                "a",
+                Expr::Choice(
+                    Box::new(Expr::Ident("b".to_owned())),
+                    Box::new(Expr::Ident("c".to_owned()))
+                )
+            ),
+            rule("b", Expr::Str("x".to_owned())),
+            rule("c", Expr::Str("y".to_owned()))
+        ];
+        let vm = Vm::new(rules).unwrap();
+
+        let (result, events) = vm.parse_traced("a", "y");
+
+        assert!(result.is_ok());
+        assert_eq!(
+            events,
+            vec![
+                TraceEvent { rule: "a".to_owned(), pos: 0, depth: 0, outcome: TraceOutcome::Enter },
+                TraceEvent { rule: "b".to_owned(), pos: 0, depth: 1, outcome: TraceOutcome::Enter },
+                TraceEvent { rule: "b".to_owned(), pos: 0, depth: 1, outcome: TraceOutcome::Failed },
+                TraceEvent { rule: "c".to_owned(), pos: 0, depth: 1, outcome: TraceOutcome::Enter },
+                TraceEvent { rule: "c".to_owned(), pos: 0, depth: 1, outcome: TraceOutcome::Matched },
+                TraceEvent { rule: "a".to_owned(), pos: 0, depth: 0, outcome: TraceOutcome::Matched }
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_records_no_trace_events() {
+        let rules = vec![rule("a", Expr::Str("x".to_owned()))];
+        let vm = Vm::new(rules).unwrap();
+
+        assert!(vm.parse("a", "x").is_ok());
+        assert!(vm.trace.borrow().is_none());
+    }
+
     #[test]
     fn unescape_all() {
         let string = r"a\nb\x55c\u{111}d";
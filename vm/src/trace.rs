@@ -0,0 +1,30 @@
+// pest. The Elegant Parser
+// Copyright (c) 2018 Dragoș Tiselice
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+/// Whether a traced rule attempt entered, matched, or failed (and thus
+/// backtracked into whichever alternative or repetition called it).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceOutcome {
+    Enter,
+    Matched,
+    Failed
+}
+
+/// A single rule attempt recorded by `Vm::parse_traced`.
+///
+/// `depth` is the nesting level of the call, so consumers can render an
+/// indented call tree the same way a left-recursion cycle is rendered as a
+/// chain of successive calls.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEvent {
+    pub rule: String,
+    pub pos: usize,
+    pub depth: usize,
+    pub outcome: TraceOutcome
+}